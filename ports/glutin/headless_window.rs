@@ -8,6 +8,7 @@ use crate::events_loop::EventsLoop;
 use crate::window_trait::WindowPortsMethods;
 use euclid::{Point2D, Rotation3D, Scale, Size2D, UnknownUnit, Vector3D};
 use winit;
+use gleam::gl;
 use servo::compositing::windowing::{AnimationState, WindowEvent};
 use servo::compositing::windowing::{EmbedderCoordinates, WindowMethods};
 use servo::servo_geometry::DeviceIndependentPixel;
@@ -15,20 +16,113 @@ use servo::style_traits::DevicePixel;
 use servo::webrender_api::units::{DeviceIntRect, DeviceIntSize};
 use servo_media::player::context as MediaPlayerCtxt;
 use servo::webrender_surfman::WebrenderSurfman;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::path::Path;
 use std::rc::Rc;
 use surfman::Connection;
 use surfman::Device;
 use surfman::NativeWidget;
 use surfman::SurfaceType;
 
+/// Scale a CSS-pixel size up to device pixels by the HiDPI factor.
+fn scale_by_dpr(size: Size2D<i32, DeviceIndependentPixel>, dpr: f32) -> DeviceIntSize {
+    DeviceIntSize::new(
+        (size.width as f32 * dpr) as i32,
+        (size.height as f32 * dpr) as i32,
+    )
+}
+
+/// Smallest page zoom we'll accept. Guards `unscale_by_page_zoom()`'s division
+/// against a zero, negative, or non-finite factor turning into `inf`/`NaN`,
+/// which `as i32` would otherwise silently saturate into a bogus coordinate
+/// reported to script as `window.outerWidth`/`outerHeight`.
+const MIN_PAGE_ZOOM: f32 = 0.01;
+
+/// Clamp a requested page zoom factor to a sane positive, finite value.
+fn clamp_page_zoom(page_zoom: f32) -> f32 {
+    if page_zoom.is_finite() {
+        page_zoom.max(MIN_PAGE_ZOOM)
+    } else {
+        MIN_PAGE_ZOOM
+    }
+}
+
+/// Shrink a CSS-pixel size by the page zoom factor.
+fn unscale_by_page_zoom(
+    size: Size2D<i32, DeviceIndependentPixel>,
+    page_zoom: f32,
+) -> Size2D<i32, DeviceIndependentPixel> {
+    Size2D::new(
+        (size.width as f32 / page_zoom) as i32,
+        (size.height as f32 / page_zoom) as i32,
+    )
+}
+
+type XrPose = (Rotation3D<f32, UnknownUnit, UnknownUnit>, Vector3D<f32, UnknownUnit>);
+
+/// Look up the pose for `frame_index`, cycling back to the start once the
+/// recorded sequence is exhausted. Returns `None` if no poses were loaded.
+fn pose_at(poses: &[XrPose], frame_index: usize) -> Option<XrPose> {
+    if poses.is_empty() {
+        None
+    } else {
+        Some(poses[frame_index % poses.len()].clone())
+    }
+}
+
+/// Tracks which half of the current frame's WebXR pose has been sampled, so
+/// the frame index only advances once both `get_rotation()` and
+/// `get_translation()` have been read for it, in either order. Repeated reads
+/// of one half before the other half is read count once; a read made after
+/// the index has advanced counts toward the next frame.
+#[derive(Default)]
+struct FrameSampler {
+    frame_index: Cell<usize>,
+    rotation_sampled: Cell<bool>,
+    translation_sampled: Cell<bool>,
+}
+
+impl FrameSampler {
+    fn frame_index(&self) -> usize {
+        self.frame_index.get()
+    }
+
+    fn mark_sampled(&self, rotation: bool) {
+        if rotation {
+            self.rotation_sampled.set(true);
+        } else {
+            self.translation_sampled.set(true);
+        }
+        if self.rotation_sampled.get() && self.translation_sampled.get() {
+            self.frame_index.set(self.frame_index.get() + 1);
+            self.rotation_sampled.set(false);
+            self.translation_sampled.set(false);
+        }
+    }
+}
+
+/// Flip the rows of a tightly-packed RGBA8 image, converting between GL's
+/// bottom-left-origin framebuffer and image formats that run rows top-down.
+fn flip_rows(pixels: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let stride = width * 4;
+    let mut flipped = vec![0; pixels.len()];
+    for y in 0..height {
+        let src = &pixels[y * stride..(y + 1) * stride];
+        let dst_start = (height - y - 1) * stride;
+        flipped[dst_start..dst_start + stride].copy_from_slice(src);
+    }
+    flipped
+}
+
 pub struct Window {
     webrender_surfman: WebrenderSurfman,
     animation_state: Cell<AnimationState>,
     fullscreen: Cell<bool>,
-    device_pixels_per_px: Option<f32>,
+    device_pixels_per_px: Cell<f32>,
     inner_size: Cell<Size2D<i32, DeviceIndependentPixel>>,
     size_changed: Cell<bool>, // We need to transmit resize events, but don't have/need an event queue
+    webxr_poses: Rc<RefCell<Vec<XrPose>>>,
+    page_zoom: Cell<f32>,
 }
 
 impl Window {
@@ -50,19 +144,41 @@ impl Window {
             webrender_surfman,
             animation_state: Cell::new(AnimationState::Idle),
             fullscreen: Cell::new(false),
-            device_pixels_per_px,
+            device_pixels_per_px: Cell::new(device_pixels_per_px.unwrap_or(1.0)),
             inner_size: Cell::new(size.to_i32()),
             size_changed: Cell::new(false),
+            webxr_poses: Rc::new(RefCell::new(Vec::new())),
+            page_zoom: Cell::new(1.0),
         };
 
         Rc::new(window)
     }
 
     fn servo_hidpi_factor(&self) -> Scale<f32, DeviceIndependentPixel, DevicePixel> {
-        match self.device_pixels_per_px {
-            Some(device_pixels_per_px) => Scale::new(device_pixels_per_px),
-            _ => Scale::new(1.0),
-        }
+        Scale::new(self.device_pixels_per_px.get())
+    }
+
+    /// The surfman surface size, in CSS/device-independent pixels.
+    fn css_pixel_size(&self) -> Size2D<i32, DeviceIndependentPixel> {
+        self.webrender_surfman
+            .context_surface_info()
+            .unwrap_or(None)
+            .map(|info| Size2D::from_untyped(info.size))
+            .unwrap_or(Size2D::new(0, 0))
+    }
+
+    /// The surfman surface size scaled up to device pixels by the HiDPI factor.
+    /// Shared by `page_height()`, `read_framebuffer()`, and `save_png()`;
+    /// `get_coordinates()` applies the same `scale_by_dpr()` to the surface size.
+    fn device_pixel_size(&self) -> DeviceIntSize {
+        scale_by_dpr(self.css_pixel_size(), self.servo_hidpi_factor().get())
+    }
+
+    /// Set the page zoom (pinch zoom) factor applied on top of the HiDPI scale
+    /// when reporting CSS-pixel coordinates such as `window.outerWidth`.
+    pub fn set_page_zoom(&self, page_zoom: f32) {
+        self.page_zoom.set(clamp_page_zoom(page_zoom));
+        self.size_changed.set(true);
     }
 }
 
@@ -91,6 +207,11 @@ impl WindowPortsMethods for Window {
         self.size_changed.set(true);
     }
 
+    fn set_device_pixels_per_px(&self, device_pixels_per_px: f32) {
+        self.device_pixels_per_px.set(device_pixels_per_px);
+        self.size_changed.set(true);
+    }
+
     fn has_events(&self) -> bool {
         self.size_changed.get()
     }
@@ -100,13 +221,7 @@ impl WindowPortsMethods for Window {
     }
 
     fn page_height(&self) -> f32 {
-        let height = self.webrender_surfman
-            .context_surface_info()
-            .unwrap_or(None)
-            .map(|info| info.size.height)
-            .unwrap_or(0);
-        let dpr = self.servo_hidpi_factor();
-        height as f32 * dpr.get()
+        self.device_pixel_size().height as f32
     }
 
     fn set_fullscreen(&self, state: bool) {
@@ -127,25 +242,95 @@ impl WindowPortsMethods for Window {
     }
 
     fn new_glwindow(&self, _events_loop: &EventsLoop) -> Box<dyn webxr::glwindow::GlWindow> {
-        unimplemented!()
+        Box::new(HeadlessGlWindow {
+            webrender_surfman: self.webrender_surfman.clone(),
+            poses: self.webxr_poses.clone(),
+            sampler: FrameSampler::default(),
+        })
+    }
+
+    /// Load a deterministic sequence of WebXR head poses from a JSON file of
+    /// `[[x, y, z, w], [x, y, z]]` `(rotation, translation)` pairs, to be played
+    /// back frame-by-frame by the `GlWindow` returned from `new_glwindow()`.
+    /// The pose list is shared with any `GlWindow`s already handed out, so
+    /// loading poses after a WebXR session has started still takes effect.
+    fn load_webxr_poses(&self, path: &Path) {
+        let data = std::fs::read_to_string(path).expect("Failed to read WebXR pose file");
+        let raw: Vec<((f32, f32, f32, f32), (f32, f32, f32))> =
+            serde_json::from_str(&data).expect("Failed to parse WebXR pose file");
+        let poses = raw
+            .into_iter()
+            .map(|((x, y, z, w), (tx, ty, tz))| {
+                (Rotation3D::quaternion(x, y, z, w), Vector3D::new(tx, ty, tz))
+            })
+            .collect();
+        *self.webxr_poses.borrow_mut() = poses;
+    }
+
+    /// Read back the pixels of the last composited frame as tightly-packed RGBA8,
+    /// flipped so that row 0 is the top of the image.
+    fn read_framebuffer(&self) -> Vec<u8> {
+        let device = self.webrender_surfman.device();
+        let context = self.webrender_surfman.context();
+        self.webrender_surfman
+            .make_context_current()
+            .expect("Failed to make GL context current");
+        // No current surface is an ordinary, recoverable case elsewhere in this
+        // file (`get_coordinates`, `save_png`'s own size lookup); match that here
+        // rather than panicking, since `save_png` calls straight into us.
+        let info = match device.context_surface_info(&context).unwrap_or(None) {
+            Some(info) => info,
+            None => return Vec::new(),
+        };
+
+        let gl = unsafe {
+            gl::GlFns::load_with(|symbol| device.get_proc_address(&context, symbol))
+        };
+
+        let size = self.device_pixel_size();
+        let (width, height) = (size.width, size.height);
+
+        gl.bind_framebuffer(gl::FRAMEBUFFER, info.framebuffer_object);
+        let pixels = gl.read_pixels(0, 0, width, height, gl::RGBA, gl::UNSIGNED_BYTE);
+        assert_eq!(pixels.len(), (width * height * 4) as usize);
+
+        flip_rows(&pixels, width as usize, height as usize)
+    }
+
+    fn save_png(&self, path: &Path) {
+        let size = self.device_pixel_size();
+        let pixels = self.read_framebuffer();
+        if size.width == 0 || size.height == 0 || pixels.is_empty() {
+            // No surface to read from (e.g. the session exited before
+            // compositing a frame) - nothing to write, rather than handing
+            // a zero-dimension image to a PNG encoder that will reject it.
+            return;
+        }
+        image::save_buffer(path, &pixels, size.width as u32, size.height as u32, image::ColorType::Rgba8)
+            .expect("Failed to save screenshot");
     }
 }
 
 impl WindowMethods for Window {
      fn get_coordinates(&self) -> EmbedderCoordinates {
         let dpr = self.servo_hidpi_factor();
-        let size = self.webrender_surfman
-            .context_surface_info()
-            .unwrap_or(None)
-            .map(|info| Size2D::from_untyped(info.size))
-            .unwrap_or(Size2D::new(0, 0));
-        let viewport = DeviceIntRect::new(Point2D::zero(), size);
+        // The raw surfman surface size is in CSS/device-independent pixels, same
+        // as `page_height()` and `read_framebuffer()`/`save_png()` assume.
+        let size = self.css_pixel_size();
+
+        // Device pixels: scale the CSS size up by the HiDPI factor.
+        let framebuffer = scale_by_dpr(size, dpr.get());
+        let viewport = DeviceIntRect::new(Point2D::zero(), framebuffer);
+
+        // CSS pixels: undo page zoom only, since `size` is already unscaled by dpr.
+        let window_size = unscale_by_page_zoom(size, self.page_zoom.get());
+
         EmbedderCoordinates {
             viewport,
-            framebuffer: size,
-            window: (size, Point2D::zero()),
-            screen: size,
-            screen_avail: size,
+            framebuffer,
+            window: (window_size, Point2D::zero()),
+            screen: framebuffer,
+            screen_avail: framebuffer,
             hidpi_factor: dpr,
         }
     }
@@ -171,16 +356,137 @@ impl WindowMethods for Window {
     }
 }
 
-impl webxr::glwindow::GlWindow for Window {
+/// Entry point for servoshell's headless `-o output.png`/`--exit` flow: once
+/// the compositor has composited a frame and is about to quit, save it to
+/// `output_file` if one was given on the command line. Call this from the
+/// embedder's main loop at the point where the `-o`/`--exit` options are
+/// currently handled.
+pub fn handle_headless_output(window: &dyn WindowPortsMethods, output_file: Option<&Path>) {
+    if let Some(path) = output_file {
+        window.save_png(path);
+    }
+}
+
+/// A synthetic headset for scripted WebXR sessions. Plays back a fixed sequence
+/// of poses recorded by `Window::load_webxr_poses()`, advancing to the next pose
+/// once both its rotation and translation have been sampled, so a CI run gets
+/// fully reproducible head motion with no real hardware involved.
+struct HeadlessGlWindow {
+    webrender_surfman: WebrenderSurfman,
+    poses: Rc<RefCell<Vec<XrPose>>>,
+    sampler: FrameSampler,
+}
+
+impl HeadlessGlWindow {
+    fn current_pose(&self) -> XrPose {
+        pose_at(&self.poses.borrow(), self.sampler.frame_index())
+            .unwrap_or((Rotation3D::identity(), Vector3D::zero()))
+    }
+}
+
+impl webxr::glwindow::GlWindow for HeadlessGlWindow {
     fn get_native_widget(&self, _device: &Device) -> NativeWidget {
-        unimplemented!()
+        self.webrender_surfman
+            .context_surface_info()
+            .expect("Failed to get surface info")
+            .expect("No current surface")
+            .native_widget
     }
 
     fn get_rotation(&self) -> Rotation3D<f32, UnknownUnit, UnknownUnit> {
-        Rotation3D::identity()
+        let (rotation, _) = self.current_pose();
+        self.sampler.mark_sampled(true);
+        rotation
     }
 
     fn get_translation(&self) -> Vector3D<f32, UnknownUnit> {
-        Vector3D::zero()
+        let (_, translation) = self.current_pose();
+        self.sampler.mark_sampled(false);
+        translation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_pose(tx: f32) -> XrPose {
+        (Rotation3D::identity(), Vector3D::new(tx, 0.0, 0.0))
+    }
+
+    #[test]
+    fn pose_at_cycles_back_to_the_start() {
+        let poses = vec![test_pose(0.0), test_pose(1.0), test_pose(2.0)];
+        assert_eq!(pose_at(&poses, 0).unwrap().1.x, 0.0);
+        assert_eq!(pose_at(&poses, 2).unwrap().1.x, 2.0);
+        assert_eq!(pose_at(&poses, 3).unwrap().1.x, 0.0);
+        assert_eq!(pose_at(&poses, 7).unwrap().1.x, 1.0);
+    }
+
+    #[test]
+    fn pose_at_empty_returns_none() {
+        assert!(pose_at(&[], 0).is_none());
+    }
+
+    #[test]
+    fn frame_sampler_advances_once_per_frame_regardless_of_call_order() {
+        let sampler = FrameSampler::default();
+
+        // Two rotation reads (e.g. one per eye) followed by translation should
+        // still only advance the frame once.
+        sampler.mark_sampled(true);
+        sampler.mark_sampled(true);
+        assert_eq!(sampler.frame_index(), 0);
+        sampler.mark_sampled(false);
+        assert_eq!(sampler.frame_index(), 1);
+
+        // Translation sampled before rotation should also advance only once.
+        sampler.mark_sampled(false);
+        assert_eq!(sampler.frame_index(), 1);
+        sampler.mark_sampled(true);
+        assert_eq!(sampler.frame_index(), 2);
+    }
+
+    #[test]
+    fn clamp_page_zoom_rejects_non_positive_and_non_finite_values() {
+        assert_eq!(clamp_page_zoom(2.0), 2.0);
+        assert_eq!(clamp_page_zoom(0.0), MIN_PAGE_ZOOM);
+        assert_eq!(clamp_page_zoom(-1.0), MIN_PAGE_ZOOM);
+        assert_eq!(clamp_page_zoom(f32::NAN), MIN_PAGE_ZOOM);
+        assert_eq!(clamp_page_zoom(f32::INFINITY), MIN_PAGE_ZOOM);
+    }
+
+    #[test]
+    fn unscale_by_page_zoom_divides_css_size() {
+        let size = Size2D::new(200, 100);
+        assert_eq!(unscale_by_page_zoom(size, 2.0), Size2D::new(100, 50));
+        assert_eq!(unscale_by_page_zoom(size, 1.0), size);
+    }
+
+    #[test]
+    fn scale_by_dpr_multiplies_css_size() {
+        let size = Size2D::new(200, 100);
+        assert_eq!(scale_by_dpr(size, 2.0), DeviceIntSize::new(400, 200));
+        assert_eq!(scale_by_dpr(size, 1.0).to_untyped(), size.to_untyped());
+    }
+
+    #[test]
+    fn flip_rows_reverses_row_order() {
+        // 2x2 RGBA8 image: row 0 is red, row 1 is blue.
+        let pixels = vec![
+            255, 0, 0, 255, 255, 0, 0, 255, // row 0
+            0, 0, 255, 255, 0, 0, 255, 255, // row 1
+        ];
+        let flipped = flip_rows(&pixels, 2, 2);
+        assert_eq!(&flipped[0..8], &[0, 0, 255, 255, 0, 0, 255, 255]);
+        assert_eq!(&flipped[8..16], &[255, 0, 0, 255, 255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn flip_rows_is_its_own_inverse() {
+        let pixels: Vec<u8> = (0..(4 * 3 * 4)).map(|n| n as u8).collect();
+        let flipped = flip_rows(&pixels, 4, 3);
+        let restored = flip_rows(&flipped, 4, 3);
+        assert_eq!(restored, pixels);
     }
 }